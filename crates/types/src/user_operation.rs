@@ -16,15 +16,11 @@ use ethers::{
     types::{Address, Bytes, H256, U256},
     utils::keccak256,
 };
-use strum::IntoEnumIterator;
 
-use crate::{
-    entity::{Entity, EntityType},
-    UserOperation,
-};
+use crate::entity::Entity;
 
-/// Number of bytes in the fixed size portion of an ABI encoded user operation
-const PACKED_USER_OPERATION_FIXED_LEN: usize = 480;
+pub mod v0_6;
+pub mod v0_7;
 
 /// Unique identifier for a user operation from a given sender
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -33,267 +29,541 @@ pub struct UserOperationId {
     nonce: U256,
 }
 
+impl UserOperationId {
+    pub(crate) fn new(sender: Address, nonce: U256) -> Self {
+        Self { sender, nonce }
+    }
+}
+
+/// A user operation for either the v0.6 or v0.7 EntryPoint contract.
+///
+/// EntryPoint versions disagree on calldata layout (v0.7 splits `initCode` and
+/// `paymasterAndData` into dedicated fields and bit-packs gas limits), but a
+/// bundler needs to treat ops from either version uniformly once accepted into
+/// the mempool. This enum carries either layout behind one type and dispatches
+/// hashing, packing, and entity lookups to the version it was built with.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum UserOperation {
+    /// A user operation targeting the v0.6 EntryPoint
+    V0_6(v0_6::UserOperation),
+    /// A user operation targeting the v0.7 EntryPoint
+    V0_7(v0_7::UserOperation),
+}
+
 impl UserOperation {
     /// Hash a user operation with the given entry point and chain ID.
     ///
     /// The hash is used to uniquely identify a user operation in the entry point.
     /// It does not include the signature field.
     pub fn op_hash(&self, entry_point: Address, chain_id: u64) -> H256 {
-        keccak256(encode(&[
-            Token::FixedBytes(keccak256(self.pack_for_hash()).to_vec()),
-            Token::Address(entry_point),
-            Token::Uint(chain_id.into()),
-        ]))
-        .into()
+        match self {
+            UserOperation::V0_6(uo) => uo.op_hash(entry_point, chain_id),
+            UserOperation::V0_7(uo) => uo.op_hash(entry_point, chain_id),
+        }
     }
 
     /// Get the unique identifier for this user operation from its sender
     pub fn id(&self) -> UserOperationId {
-        UserOperationId {
-            sender: self.sender,
-            nonce: self.nonce,
+        match self {
+            UserOperation::V0_6(uo) => uo.id(),
+            UserOperation::V0_7(uo) => uo.id(),
         }
     }
 
     /// Get the address of the factory entity associated with this user operation, if any
     pub fn factory(&self) -> Option<Address> {
-        Self::get_address_from_field(&self.init_code)
+        match self {
+            UserOperation::V0_6(uo) => uo.factory(),
+            UserOperation::V0_7(uo) => uo.factory(),
+        }
     }
 
     /// Get the address of the paymaster entity associated with this user operation, if any
     pub fn paymaster(&self) -> Option<Address> {
-        Self::get_address_from_field(&self.paymaster_and_data)
-    }
-
-    /// Extracts an address from the beginning of a data field
-    /// Useful to extract the paymaster address from paymaster_and_data
-    /// and the factory address from init_code
-    pub fn get_address_from_field(data: &Bytes) -> Option<Address> {
-        if data.len() < 20 {
-            None
-        } else {
-            Some(Address::from_slice(&data[..20]))
+        match self {
+            UserOperation::V0_6(uo) => uo.paymaster(),
+            UserOperation::V0_7(uo) => uo.paymaster(),
         }
     }
 
     /// Efficient calculation of the size of a packed user operation
     pub fn abi_encoded_size(&self) -> usize {
-        PACKED_USER_OPERATION_FIXED_LEN
-            + pad_len(&self.init_code)
-            + pad_len(&self.call_data)
-            + pad_len(&self.paymaster_and_data)
-            + pad_len(&self.signature)
+        match self {
+            UserOperation::V0_6(uo) => uo.abi_encoded_size(),
+            UserOperation::V0_7(uo) => uo.abi_encoded_size(),
+        }
     }
 
     /// Compute the amount of heap memory the UserOperation takes up.
     pub fn heap_size(&self) -> usize {
-        self.init_code.len()
-            + self.call_data.len()
-            + self.paymaster_and_data.len()
-            + self.signature.len()
+        match self {
+            UserOperation::V0_6(uo) => uo.heap_size(),
+            UserOperation::V0_7(uo) => uo.heap_size(),
+        }
     }
 
     /// Gets the byte array representation of the user operation to be used in the signature
     pub fn pack_for_hash(&self) -> Bytes {
-        let hash_init_code = keccak256(self.init_code.clone());
-        let hash_call_data = keccak256(self.call_data.clone());
-        let hash_paymaster_and_data = keccak256(self.paymaster_and_data.clone());
-
-        encode(&[
-            Token::Address(self.sender),
-            Token::Uint(self.nonce),
-            Token::FixedBytes(hash_init_code.to_vec()),
-            Token::FixedBytes(hash_call_data.to_vec()),
-            Token::Uint(self.call_gas_limit),
-            Token::Uint(self.verification_gas_limit),
-            Token::Uint(self.pre_verification_gas),
-            Token::Uint(self.max_fee_per_gas),
-            Token::Uint(self.max_priority_fee_per_gas),
-            Token::FixedBytes(hash_paymaster_and_data.to_vec()),
-        ])
-        .into()
+        match self {
+            UserOperation::V0_6(uo) => uo.pack_for_hash(),
+            UserOperation::V0_7(uo) => uo.pack_for_hash(),
+        }
     }
 
     /// Gets an iterator on all entities associated with this user operation
-    pub fn entities(&'_ self) -> impl Iterator<Item = Entity> + '_ {
-        EntityType::iter().filter_map(|entity| {
-            self.entity_address(entity)
-                .map(|address| Entity::new(entity, address))
-        })
-    }
-
-    /// Gets the address of the entity of the given type associated with this user operation, if any
-    fn entity_address(&self, entity: EntityType) -> Option<Address> {
-        match entity {
-            EntityType::Account => Some(self.sender),
-            EntityType::Paymaster => self.paymaster(),
-            EntityType::Factory => self.factory(),
-            EntityType::Aggregator => None,
+    pub fn entities(&'_ self) -> Box<dyn Iterator<Item = Entity> + '_> {
+        match self {
+            UserOperation::V0_6(uo) => Box::new(uo.entities()),
+            UserOperation::V0_7(uo) => Box::new(uo.entities()),
+        }
+    }
+
+    /// Hash a user operation with the given entry point and chain ID, reusing the field hashes
+    /// in `cache` where possible.
+    ///
+    /// Equivalent to [`op_hash`](Self::op_hash), but for a `UserOperation` that is hashed
+    /// against many entry points (once per configured entry point, on every mempool lookup,
+    /// during simulation, ...) this avoids recomputing the `keccak256` of `init_code`,
+    /// `call_data`, and `paymaster_and_data` on every call.
+    pub fn op_hash_cached(
+        &self,
+        cache: &mut UserOperationHashCache,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> H256 {
+        if cache.fingerprint != self.hash_cache_fingerprint() {
+            *cache = UserOperationHashCache::new(self);
+        }
+
+        let hash_init_code = *cache
+            .hash_init_code
+            .get_or_insert_with(|| keccak256(self.init_code_for_hash()).into());
+        let hash_call_data = *cache
+            .hash_call_data
+            .get_or_insert_with(|| keccak256(self.call_data().clone()).into());
+        let hash_paymaster_and_data = *cache
+            .hash_paymaster_and_data
+            .get_or_insert_with(|| keccak256(self.paymaster_and_data_for_hash()).into());
+        let packed = cache.packed.get_or_insert_with(|| {
+            self.pack_for_hash_from(hash_init_code, hash_call_data, hash_paymaster_and_data)
+        });
+
+        keccak256(encode(&[
+            Token::FixedBytes(keccak256(packed.clone()).to_vec()),
+            Token::Address(entry_point),
+            Token::Uint(chain_id.into()),
+        ]))
+        .into()
+    }
+
+    /// Cheap, non-cryptographic fingerprint used to sanity-check that a [`UserOperationHashCache`]
+    /// still matches the `UserOperation` it was built from.
+    ///
+    /// Tracks the length of each field that feeds a distinct sub-hash in [`pack_for_hash`]
+    /// individually, rather than a single summed size: two ops with the same total byte volume
+    /// but a different split between `init_code` and `paymaster_and_data` (e.g. swapping a
+    /// longer factory blob for a longer paymaster blob) must not collide, or a reused cache would
+    /// silently serve the wrong sub-hashes instead of just a stale one.
+    ///
+    /// [`pack_for_hash`]: Self::pack_for_hash
+    fn hash_cache_fingerprint(&self) -> (usize, usize, usize) {
+        (
+            self.init_code_len_for_hash(),
+            self.call_data().len(),
+            self.paymaster_and_data_len_for_hash(),
+        )
+    }
+
+    fn call_data(&self) -> &Bytes {
+        match self {
+            UserOperation::V0_6(uo) => &uo.call_data,
+            UserOperation::V0_7(uo) => &uo.call_data,
+        }
+    }
+
+    fn init_code_for_hash(&self) -> Bytes {
+        match self {
+            UserOperation::V0_6(uo) => uo.init_code.clone(),
+            UserOperation::V0_7(uo) => uo.init_code(),
+        }
+    }
+
+    fn init_code_len_for_hash(&self) -> usize {
+        match self {
+            UserOperation::V0_6(uo) => uo.init_code.len(),
+            UserOperation::V0_7(uo) => uo.init_code_len(),
+        }
+    }
+
+    fn paymaster_and_data_for_hash(&self) -> Bytes {
+        match self {
+            UserOperation::V0_6(uo) => uo.paymaster_and_data.clone(),
+            UserOperation::V0_7(uo) => uo.paymaster_and_data(),
+        }
+    }
+
+    fn paymaster_and_data_len_for_hash(&self) -> usize {
+        match self {
+            UserOperation::V0_6(uo) => uo.paymaster_and_data.len(),
+            UserOperation::V0_7(uo) => uo.paymaster_and_data_len(),
+        }
+    }
+
+    /// Computes the effective gas price this operation pays given a block `base_fee`, per
+    /// EIP-1559: the effective priority fee is capped at `max_fee_per_gas - base_fee`, and the
+    /// effective gas price is `base_fee` plus that capped tip.
+    ///
+    /// Returns zero if `base_fee` exceeds `max_fee_per_gas`, since the operation cannot be
+    /// included in that block. At `base_fee == max_fee_per_gas` the op is still includable, at a
+    /// price of exactly `max_fee_per_gas` with zero tip.
+    pub fn effective_gas_price(&self, base_fee: U256) -> U256 {
+        let max_fee_per_gas = self.max_fee_per_gas();
+        if base_fee > max_fee_per_gas {
+            return U256::zero();
+        }
+        let effective_priority_fee = self
+            .max_priority_fee_per_gas()
+            .min(max_fee_per_gas - base_fee);
+        base_fee + effective_priority_fee
+    }
+
+    /// Computes the maximum amount of native currency the EntryPoint could charge this
+    /// operation's sender (or paymaster) to prefund it, i.e. `max_fee_per_gas` times the sum of
+    /// `pre_verification_gas`, `verification_gas_limit`, and `call_gas_limit`. For a v0.7
+    /// operation, `paymaster_verification_gas_limit` and `paymaster_post_op_gas_limit` are not
+    /// included in that sum.
+    ///
+    /// Returns `None` on overflow rather than wrapping. Gas limits and `max_fee_per_gas` are
+    /// unvalidated fields straight off the mempool, so an overflowing product must be surfaced
+    /// to the caller to reject, not panic the whole process.
+    pub fn max_gas_cost(&self) -> Option<U256> {
+        self.max_fee_per_gas().checked_mul(self.total_gas_limit()?)
+    }
+
+    /// Computes the prefund the EntryPoint will actually charge this operation's sender (or
+    /// paymaster) given a block `base_fee`: the same total gas as [`max_gas_cost`](Self::max_gas_cost),
+    /// priced at [`effective_gas_price`](Self::effective_gas_price) instead of `max_fee_per_gas`.
+    ///
+    /// Returns `None` on overflow, for the same reason as `max_gas_cost`.
+    pub fn required_prefund(&self, base_fee: U256) -> Option<U256> {
+        self.effective_gas_price(base_fee)
+            .checked_mul(self.total_gas_limit()?)
+    }
+
+    /// Sums `pre_verification_gas`, `verification_gas_limit`, and `call_gas_limit`, or `None` on
+    /// overflow.
+    fn total_gas_limit(&self) -> Option<U256> {
+        self.pre_verification_gas()
+            .checked_add(self.verification_gas_limit())
+            .and_then(|sum| sum.checked_add(self.call_gas_limit()))
+    }
+
+    fn max_fee_per_gas(&self) -> U256 {
+        match self {
+            UserOperation::V0_6(uo) => uo.max_fee_per_gas,
+            UserOperation::V0_7(uo) => uo.max_fee_per_gas,
+        }
+    }
+
+    fn max_priority_fee_per_gas(&self) -> U256 {
+        match self {
+            UserOperation::V0_6(uo) => uo.max_priority_fee_per_gas,
+            UserOperation::V0_7(uo) => uo.max_priority_fee_per_gas,
+        }
+    }
+
+    fn pre_verification_gas(&self) -> U256 {
+        match self {
+            UserOperation::V0_6(uo) => uo.pre_verification_gas,
+            UserOperation::V0_7(uo) => uo.pre_verification_gas,
+        }
+    }
+
+    fn verification_gas_limit(&self) -> U256 {
+        match self {
+            UserOperation::V0_6(uo) => uo.verification_gas_limit,
+            UserOperation::V0_7(uo) => uo.verification_gas_limit,
+        }
+    }
+
+    fn call_gas_limit(&self) -> U256 {
+        match self {
+            UserOperation::V0_6(uo) => uo.call_gas_limit,
+            UserOperation::V0_7(uo) => uo.call_gas_limit,
+        }
+    }
+
+    fn pack_for_hash_from(
+        &self,
+        hash_init_code: H256,
+        hash_call_data: H256,
+        hash_paymaster_and_data: H256,
+    ) -> Bytes {
+        match self {
+            UserOperation::V0_6(uo) => {
+                uo.pack_for_hash_from(hash_init_code, hash_call_data, hash_paymaster_and_data)
+            }
+            UserOperation::V0_7(uo) => {
+                uo.pack_for_hash_from(hash_init_code, hash_call_data, hash_paymaster_and_data)
+            }
         }
     }
 }
 
-/// Calculates the size a byte array padded to the next largest multiple of 32
-fn pad_len(b: &Bytes) -> usize {
-    (b.len() + 31) & !31
+/// Cache of the intermediate field hashes used by [`UserOperation::pack_for_hash`] and
+/// [`UserOperation::op_hash`].
+///
+/// Populated lazily by [`UserOperation::op_hash_cached`]: each slot is filled on first use and
+/// reused on subsequent calls, so hashing the same op against N entry points does one
+/// `keccak256` per hashed field instead of N.
+///
+/// The cache is only valid for the exact `UserOperation` it was built from. Since
+/// `UserOperation`'s fields are public and nothing ties a cache to a specific instance,
+/// `op_hash_cached` guards against stale reuse with a cheap fingerprint (the individual lengths
+/// of `init_code`, `call_data`, and `paymaster_and_data`) and silently rebuilds the cache if it no
+/// longer matches — but this is a sanity check, not a guarantee, since two different ops can
+/// still share a fingerprint. Callers that mutate an op after hashing it should construct a fresh
+/// cache rather than rely on this check.
+#[derive(Debug, Default, Clone)]
+pub struct UserOperationHashCache {
+    fingerprint: (usize, usize, usize),
+    hash_init_code: Option<H256>,
+    hash_call_data: Option<H256>,
+    hash_paymaster_and_data: Option<H256>,
+    packed: Option<Bytes>,
 }
 
-#[cfg(test)]
-mod tests {
-    use std::str::FromStr;
+impl UserOperationHashCache {
+    /// Creates an empty cache fingerprinted against `op`.
+    pub fn new(op: &UserOperation) -> Self {
+        Self {
+            fingerprint: op.hash_cache_fingerprint(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<v0_6::UserOperation> for UserOperation {
+    fn from(op: v0_6::UserOperation) -> Self {
+        UserOperation::V0_6(op)
+    }
+}
 
-    use ethers::{
-        abi::AbiEncode,
-        types::{Bytes, U256},
-    };
+impl From<v0_7::UserOperation> for UserOperation {
+    fn from(op: v0_7::UserOperation) -> Self {
+        UserOperation::V0_7(op)
+    }
+}
+
+/// Calculates the size a byte array of the given length is padded to, rounded up to the next
+/// largest multiple of 32.
+pub(crate) fn pad_len(len: usize) -> usize {
+    (len + 31) & !31
+}
 
+#[cfg(test)]
+mod tests {
     use super::*;
 
-    #[test]
-    fn test_hash_zeroed() {
-        // Testing a user operation hash against the hash generated by the
-        // entrypoint contract getUserOpHash() function with entrypoint address
-        // at 0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc and chain ID 1337.
-        //
-        // UserOperation = {
-        //     sender: '0x0000000000000000000000000000000000000000',
-        //     nonce: 0,
-        //     initCode: '0x',
-        //     callData: '0x',
-        //     callGasLimit: 0,
-        //     verificationGasLimit: 0,
-        //     preVerificationGas: 0,
-        //     maxFeePerGas: 0,
-        //     maxPriorityFeePerGas: 0,
-        //     paymasterAndData: '0x',
-        //     signature: '0x',
-        //   }
-        //
-        // Hash: 0xdca97c3b49558ab360659f6ead939773be8bf26631e61bb17045bb70dc983b2d
-        let operation = UserOperation {
-            sender: "0x0000000000000000000000000000000000000000"
-                .parse()
-                .unwrap(),
-            nonce: U256::zero(),
-            init_code: Bytes::default(),
-            call_data: Bytes::default(),
-            call_gas_limit: U256::zero(),
-            verification_gas_limit: U256::zero(),
-            pre_verification_gas: U256::zero(),
-            max_fee_per_gas: U256::zero(),
-            max_priority_fee_per_gas: U256::zero(),
-            paymaster_and_data: Bytes::default(),
-            signature: Bytes::default(),
-        };
-        let entry_point = "0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc"
-            .parse()
-            .unwrap();
-        let chain_id = 1337;
-        let hash = operation.op_hash(entry_point, chain_id);
-        assert_eq!(
-            hash,
-            "0xdca97c3b49558ab360659f6ead939773be8bf26631e61bb17045bb70dc983b2d"
-                .parse()
-                .unwrap()
-        );
+    fn op_with_call_data(call_data: &[u8]) -> UserOperation {
+        op_with_fields(call_data, &[], &[])
     }
 
-    #[test]
-    fn test_hash() {
-        // Testing a user operation hash against the hash generated by the
-        // entrypoint contract getUserOpHash() function with entrypoint address
-        // at 0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc and chain ID 1337.
-        //
-        // UserOperation = {
-        //     sender: '0x1306b01bc3e4ad202612d3843387e94737673f53',
-        //     nonce: 8942,
-        //     initCode: '0x6942069420694206942069420694206942069420',
-        //     callData: '0x0000000000000000000000000000000000000000080085',
-        //     callGasLimit: 10000,
-        //     verificationGasLimit: 100000,
-        //     preVerificationGas: 100,
-        //     maxFeePerGas: 99999,
-        //     maxPriorityFeePerGas: 9999999,
-        //     paymasterAndData:
-        //       '0x0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef',
-        //     signature:
-        //       '0xda0929f527cded8d0a1eaf2e8861d7f7e2d8160b7b13942f99dd367df4473a',
-        //   }
-        //
-        // Hash: 0x484add9e4d8c3172d11b5feb6a3cc712280e176d278027cfa02ee396eb28afa1
-        let operation = UserOperation {
+    fn op_with_fields(
+        call_data: &[u8],
+        init_code: &[u8],
+        paymaster_and_data: &[u8],
+    ) -> UserOperation {
+        v0_6::UserOperation {
             sender: "0x1306b01bc3e4ad202612d3843387e94737673f53"
                 .parse()
                 .unwrap(),
-            nonce: 8942.into(),
-            init_code: "0x6942069420694206942069420694206942069420"
-                .parse()
-                .unwrap(),
-            call_data: "0x0000000000000000000000000000000000000000080085"
-                .parse()
-                .unwrap(),
+            nonce: 1.into(),
+            init_code: init_code.to_vec().into(),
+            call_data: call_data.to_vec().into(),
             call_gas_limit: 10000.into(),
             verification_gas_limit: 100000.into(),
             pre_verification_gas: 100.into(),
             max_fee_per_gas: 99999.into(),
             max_priority_fee_per_gas: 9999999.into(),
-            paymaster_and_data:
-                "0x0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"
-                    .parse()
-                    .unwrap(),
-            signature: "0xda0929f527cded8d0a1eaf2e8861d7f7e2d8160b7b13942f99dd367df4473a"
-                .parse()
-                .unwrap(),
-        };
-        let entry_point = "0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc"
+            paymaster_and_data: paymaster_and_data.to_vec().into(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_op_hash_cached_matches_op_hash_across_entry_points() {
+        let op = op_with_call_data(b"hello world");
+        let mut cache = UserOperationHashCache::new(&op);
+
+        let entry_point_a: Address = "0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc"
+            .parse()
+            .unwrap();
+        let entry_point_b: Address = "0x0000000000000000000000000000000000000001"
             .parse()
             .unwrap();
         let chain_id = 1337;
-        let hash = operation.op_hash(entry_point, chain_id);
+
+        // First call against entry_point_a fills the cache.
         assert_eq!(
-            hash,
-            "0x484add9e4d8c3172d11b5feb6a3cc712280e176d278027cfa02ee396eb28afa1"
-                .parse()
-                .unwrap()
+            op.op_hash_cached(&mut cache, entry_point_a, chain_id),
+            op.op_hash(entry_point_a, chain_id)
+        );
+        // A second call against a different entry point reuses the cached field hashes, and
+        // should still match the uncached hash for that entry point.
+        assert_eq!(
+            op.op_hash_cached(&mut cache, entry_point_b, chain_id),
+            op.op_hash(entry_point_b, chain_id)
+        );
+        // Repeating the first entry point again is still correct.
+        assert_eq!(
+            op.op_hash_cached(&mut cache, entry_point_a, chain_id),
+            op.op_hash(entry_point_a, chain_id)
         );
     }
 
     #[test]
-    fn test_get_address_from_field() {
-        let paymaster_and_data: Bytes =
-            "0x0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"
-                .parse()
-                .unwrap();
-        let address = UserOperation::get_address_from_field(&paymaster_and_data).unwrap();
+    fn test_op_hash_cached_rebuilds_on_fingerprint_mismatch() {
+        let op_a = op_with_call_data(b"hello world");
+        let op_b = op_with_call_data(b"a completely different, longer call data payload");
+
+        // Build a cache against op_a, then (mis)use it to hash op_b: the fingerprint check
+        // should detect the mismatch and rebuild the cache instead of returning a stale hash.
+        let mut cache = UserOperationHashCache::new(&op_a);
+        let entry_point: Address = "0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc"
+            .parse()
+            .unwrap();
+        let chain_id = 1337;
+
         assert_eq!(
-            address,
-            "0x0123456789abcdef0123456789abcdef01234567"
-                .parse()
-                .unwrap()
+            op_b.op_hash_cached(&mut cache, entry_point, chain_id),
+            op_b.op_hash(entry_point, chain_id)
+        );
+        assert_ne!(
+            op_b.op_hash_cached(&mut cache, entry_point, chain_id),
+            op_a.op_hash(entry_point, chain_id)
         );
     }
 
     #[test]
-    fn test_abi_encoded_size() {
-        let user_operation = UserOperation {
-            sender: "0xe29a7223a7e040d70b5cd460ef2f4ac6a6ab304d"
-                .parse()
-                .unwrap(),
-            nonce: U256::from_dec_str("3937668929043450082210854285941660524781292117276598730779").unwrap(),
-            init_code: Bytes::default(),
-            call_data: Bytes::from_str("0x5194544700000000000000000000000058440a3e78b190e5bd07905a08a60e30bb78cb5b000000000000000000000000000000000000000000000000000009184e72a000000000000000000000000000000000000000000000000000000000000000008000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000").unwrap(),
-            call_gas_limit: 40_960.into(),
-            verification_gas_limit: 75_099.into(),
-            pre_verification_gas: 46_330.into(),
-            max_fee_per_gas: 105_000_000.into(),
-            max_priority_fee_per_gas: 105_000_000.into(),
-            paymaster_and_data: Bytes::from_str("0xc03aac639bb21233e0139381970328db8bceeb6700006508996f000065089a9b0000000000000000000000000000000000000000ca7517be4e51ca2cde69bc44c4c3ce00ff7f501ce4ee1b3c6b2a742f579247292e4f9a672522b15abee8eaaf1e1487b8e3121d61d42ba07a47f5ccc927aa7eb61b").unwrap(),
-            signature: Bytes::from_str("0x00000000f8a0655423f2dfbb104e0ff906b7b4c64cfc12db0ac5ef0fb1944076650ce92a1a736518e5b6cd46c6ff6ece7041f2dae199fb4c8e7531704fbd629490b712dc1b").unwrap(),
-        };
+    fn test_op_hash_cached_detects_init_code_paymaster_redistribution() {
+        // Same call_data and the same total (init_code + paymaster_and_data) byte volume, but
+        // the split between the two fields differs. A fingerprint that only tracked the summed
+        // size (e.g. via heap_size()) would treat these as identical and serve op_b a hash built
+        // from op_a's init_code/paymaster_and_data sub-hashes.
+        let op_a = op_with_fields(b"call data", &[0xaa; 10], &[0xbb; 5]);
+        let op_b = op_with_fields(b"call data", &[0xaa; 5], &[0xbb; 10]);
+
+        let mut cache = UserOperationHashCache::new(&op_a);
+        let entry_point: Address = "0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc"
+            .parse()
+            .unwrap();
+        let chain_id = 1337;
 
         assert_eq!(
-            user_operation.clone().encode().len(),
-            user_operation.abi_encoded_size()
+            op_b.op_hash_cached(&mut cache, entry_point, chain_id),
+            op_b.op_hash(entry_point, chain_id)
         );
+        assert_ne!(
+            op_b.op_hash_cached(&mut cache, entry_point, chain_id),
+            op_a.op_hash(entry_point, chain_id)
+        );
+    }
+
+    fn op_with_gas(
+        call_gas_limit: u64,
+        verification_gas_limit: u64,
+        pre_verification_gas: u64,
+        max_fee_per_gas: u64,
+        max_priority_fee_per_gas: u64,
+    ) -> UserOperation {
+        v0_6::UserOperation {
+            call_gas_limit: call_gas_limit.into(),
+            verification_gas_limit: verification_gas_limit.into(),
+            pre_verification_gas: pre_verification_gas.into(),
+            max_fee_per_gas: max_fee_per_gas.into(),
+            max_priority_fee_per_gas: max_priority_fee_per_gas.into(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_effective_gas_price_base_fee_above_max_fee_saturates_at_zero() {
+        let op = op_with_gas(100, 100, 100, 100, 10);
+        assert_eq!(op.effective_gas_price(U256::from(101)), U256::zero());
+    }
+
+    #[test]
+    fn test_effective_gas_price_base_fee_equals_max_fee() {
+        // At base_fee == max_fee_per_gas the op is still includable, with zero tip.
+        let op = op_with_gas(100, 100, 100, 100, 10);
+        assert_eq!(op.effective_gas_price(U256::from(100)), U256::from(100));
+    }
+
+    #[test]
+    fn test_effective_gas_price_caps_priority_fee() {
+        let op = op_with_gas(100, 100, 100, /* max_fee */ 100, /* max_priority */ 10);
+        // base_fee = 95 leaves only 5 of headroom before max_fee_per_gas, capping the 10 tip.
+        assert_eq!(op.effective_gas_price(U256::from(95)), U256::from(100));
+    }
+
+    #[test]
+    fn test_effective_gas_price_under_max_priority_fee() {
+        let op = op_with_gas(100, 100, 100, /* max_fee */ 100, /* max_priority */ 3);
+        // base_fee = 90 leaves 10 of headroom, more than the 3 tip, so the full tip applies.
+        assert_eq!(op.effective_gas_price(U256::from(90)), U256::from(93));
+    }
+
+    #[test]
+    fn test_max_gas_cost() {
+        let op = op_with_gas(
+            /* call_gas_limit */ 1000,
+            /* verification_gas_limit */ 2000,
+            /* pre_verification_gas */ 3000,
+            /* max_fee_per_gas */ 5,
+            /* max_priority_fee_per_gas */ 1,
+        );
+        assert_eq!(op.max_gas_cost(), Some(U256::from((1000 + 2000 + 3000) * 5)));
+    }
+
+    #[test]
+    fn test_required_prefund_uses_effective_price() {
+        let op = op_with_gas(1000, 2000, 3000, /* max_fee */ 100, /* max_priority */ 10);
+        // base_fee = 95 caps the tip at 5, so the effective price is 100, same as max_fee here.
+        assert_eq!(
+            op.required_prefund(U256::from(95)),
+            Some(U256::from((1000 + 2000 + 3000) * 100))
+        );
+    }
+
+    #[test]
+    fn test_max_gas_cost_overflow_returns_none() {
+        let op = op_with_gas(0, 0, 0, 0, 0);
+        let op = match op {
+            UserOperation::V0_6(mut uo) => {
+                uo.verification_gas_limit = U256::max_value();
+                uo.max_fee_per_gas = U256::from(2);
+                UserOperation::V0_6(uo)
+            }
+            UserOperation::V0_7(_) => unreachable!(),
+        };
+        assert_eq!(op.max_gas_cost(), None);
+    }
+
+    #[test]
+    fn test_required_prefund_overflow_returns_none() {
+        let op = op_with_gas(0, 0, 0, 0, 0);
+        let op = match op {
+            UserOperation::V0_6(mut uo) => {
+                uo.call_gas_limit = U256::max_value();
+                uo.max_fee_per_gas = U256::from(2);
+                uo.max_priority_fee_per_gas = U256::from(2);
+                UserOperation::V0_6(uo)
+            }
+            UserOperation::V0_7(_) => unreachable!(),
+        };
+        assert_eq!(op.required_prefund(U256::zero()), None);
     }
 }
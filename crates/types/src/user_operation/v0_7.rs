@@ -0,0 +1,371 @@
+// This file is part of Rundler.
+//
+// Rundler is free software: you can redistribute it and/or modify it under the
+// terms of the GNU Lesser General Public License as published by the Free Software
+// Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// Rundler is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY;
+// without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.
+// See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with Rundler.
+// If not, see https://www.gnu.org/licenses/.
+
+//! User operation types for the v0.7 EntryPoint contract interface.
+//!
+//! The v0.7 EntryPoint repacks the v0.6 calldata layout: `initCode` is split
+//! into a dedicated `factory` address and `factoryData`, `paymasterAndData` is
+//! split into `paymaster`, `paymasterVerificationGasLimit`,
+//! `paymasterPostOpGasLimit`, and `paymasterData`, and the gas limits/fees are
+//! bit-packed two-per-word into `accountGasLimits` and `gasFees`.
+
+use ethers::{
+    abi::{encode, Token},
+    types::{Address, Bytes, H256, U256},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+
+use crate::{
+    entity::{Entity, EntityType},
+    user_operation::{pad_len, UserOperationId},
+};
+
+/// Number of bytes in the fixed size portion of an ABI encoded v0.7 packed user operation
+const PACKED_USER_OPERATION_FIXED_LEN: usize = 416;
+
+/// User Operation for Entry Point v0.7
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperation {
+    pub sender: Address,
+    pub nonce: U256,
+    pub factory: Option<Address>,
+    pub factory_data: Bytes,
+    pub call_data: Bytes,
+    pub call_gas_limit: U256,
+    pub verification_gas_limit: U256,
+    pub pre_verification_gas: U256,
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+    pub paymaster: Option<Address>,
+    pub paymaster_verification_gas_limit: U256,
+    pub paymaster_post_op_gas_limit: U256,
+    pub paymaster_data: Bytes,
+    pub signature: Bytes,
+}
+
+impl UserOperation {
+    /// Hash a user operation with the given entry point and chain ID.
+    ///
+    /// The hash is used to uniquely identify a user operation in the entry point.
+    /// It does not include the signature field.
+    pub fn op_hash(&self, entry_point: Address, chain_id: u64) -> H256 {
+        keccak256(encode(&[
+            Token::FixedBytes(keccak256(self.pack_for_hash()).to_vec()),
+            Token::Address(entry_point),
+            Token::Uint(chain_id.into()),
+        ]))
+        .into()
+    }
+
+    /// Get the unique identifier for this user operation from its sender
+    pub fn id(&self) -> UserOperationId {
+        UserOperationId::new(self.sender, self.nonce)
+    }
+
+    /// Get the address of the factory entity associated with this user operation, if any
+    pub fn factory(&self) -> Option<Address> {
+        self.factory
+    }
+
+    /// Get the address of the paymaster entity associated with this user operation, if any
+    pub fn paymaster(&self) -> Option<Address> {
+        self.paymaster
+    }
+
+    /// Efficient calculation of the size of a packed user operation
+    pub fn abi_encoded_size(&self) -> usize {
+        PACKED_USER_OPERATION_FIXED_LEN
+            + pad_len(self.init_code_len())
+            + pad_len(self.call_data.len())
+            + pad_len(self.paymaster_and_data_len())
+            + pad_len(self.signature.len())
+    }
+
+    /// Length in bytes of the reconstructed `initCode` blob (`factory ++ factoryData`), without
+    /// allocating it.
+    pub(crate) fn init_code_len(&self) -> usize {
+        self.factory.map_or(0, |_| 20 + self.factory_data.len())
+    }
+
+    /// Length in bytes of the reconstructed `paymasterAndData` blob (`paymaster ++
+    /// paymasterVerificationGasLimit ++ paymasterPostOpGasLimit ++ paymasterData`), without
+    /// allocating it.
+    pub(crate) fn paymaster_and_data_len(&self) -> usize {
+        self.paymaster
+            .map_or(0, |_| 20 + 16 + 16 + self.paymaster_data.len())
+    }
+
+    /// Compute the amount of heap memory the UserOperation takes up.
+    pub fn heap_size(&self) -> usize {
+        self.factory_data.len()
+            + self.call_data.len()
+            + self.paymaster_data.len()
+            + self.signature.len()
+    }
+
+    /// Gets the byte array representation of the user operation to be used in the signature
+    pub fn pack_for_hash(&self) -> Bytes {
+        self.pack_for_hash_from(
+            keccak256(self.init_code()).into(),
+            keccak256(self.call_data.clone()).into(),
+            keccak256(self.paymaster_and_data()).into(),
+        )
+    }
+
+    /// Gets the byte array representation of the user operation to be used in the signature,
+    /// given the `keccak256` of the packed `init_code`, `call_data`, and `paymaster_and_data`.
+    ///
+    /// Useful to avoid recomputing these hashes when they are already known, e.g. from a
+    /// [`UserOperationHashCache`](super::UserOperationHashCache).
+    pub(crate) fn pack_for_hash_from(
+        &self,
+        hash_init_code: H256,
+        hash_call_data: H256,
+        hash_paymaster_and_data: H256,
+    ) -> Bytes {
+        encode(&[
+            Token::Address(self.sender),
+            Token::Uint(self.nonce),
+            Token::FixedBytes(hash_init_code.as_bytes().to_vec()),
+            Token::FixedBytes(hash_call_data.as_bytes().to_vec()),
+            Token::FixedBytes(self.account_gas_limits().as_bytes().to_vec()),
+            Token::Uint(self.pre_verification_gas),
+            Token::FixedBytes(self.gas_fees().as_bytes().to_vec()),
+            Token::FixedBytes(hash_paymaster_and_data.as_bytes().to_vec()),
+        ])
+        .into()
+    }
+
+    /// Rebuilds the v0.6-style `initCode` blob (`factory ++ factoryData`) that the EntryPoint
+    /// hashes, from the dedicated `factory`/`factory_data` fields.
+    pub(crate) fn init_code(&self) -> Bytes {
+        let Some(factory) = self.factory else {
+            return Bytes::default();
+        };
+        let mut packed = Vec::with_capacity(20 + self.factory_data.len());
+        packed.extend_from_slice(factory.as_bytes());
+        packed.extend_from_slice(&self.factory_data);
+        packed.into()
+    }
+
+    /// Rebuilds the v0.6-style `paymasterAndData` blob (`paymaster ++
+    /// paymasterVerificationGasLimit ++ paymasterPostOpGasLimit ++ paymasterData`) that the
+    /// EntryPoint hashes, from the dedicated paymaster fields.
+    pub(crate) fn paymaster_and_data(&self) -> Bytes {
+        let Some(paymaster) = self.paymaster else {
+            return Bytes::default();
+        };
+        let mut packed = Vec::with_capacity(20 + 16 + 16 + self.paymaster_data.len());
+        packed.extend_from_slice(paymaster.as_bytes());
+        packed.extend_from_slice(&pack_high_low(
+            self.paymaster_verification_gas_limit,
+            self.paymaster_post_op_gas_limit,
+        ));
+        packed.extend_from_slice(&self.paymaster_data);
+        packed.into()
+    }
+
+    /// Packs `verification_gas_limit` and `call_gas_limit` into the single 32-byte
+    /// `accountGasLimits` word the EntryPoint expects.
+    fn account_gas_limits(&self) -> H256 {
+        pack_high_low(self.verification_gas_limit, self.call_gas_limit).into()
+    }
+
+    /// Packs `max_priority_fee_per_gas` and `max_fee_per_gas` into the single 32-byte `gasFees`
+    /// word the EntryPoint expects.
+    fn gas_fees(&self) -> H256 {
+        pack_high_low(self.max_priority_fee_per_gas, self.max_fee_per_gas).into()
+    }
+
+    /// Gets an iterator on all entities associated with this user operation
+    pub fn entities(&'_ self) -> impl Iterator<Item = Entity> + '_ {
+        EntityType::iter().filter_map(|entity| {
+            self.entity_address(entity)
+                .map(|address| Entity::new(entity, address))
+        })
+    }
+
+    /// Gets the address of the entity of the given type associated with this user operation, if any
+    fn entity_address(&self, entity: EntityType) -> Option<Address> {
+        match entity {
+            EntityType::Account => Some(self.sender),
+            EntityType::Paymaster => self.paymaster,
+            EntityType::Factory => self.factory,
+            EntityType::Aggregator => None,
+        }
+    }
+}
+
+/// Packs two values, each expected to fit in 128 bits, into a single 32-byte word as
+/// `high ++ low`, matching the `accountGasLimits`/`gasFees`/paymaster gas limit packing used by
+/// the v0.7 EntryPoint.
+fn pack_high_low(high: U256, low: U256) -> [u8; 32] {
+    let mut packed = [0u8; 32];
+    let mut buf = [0u8; 32];
+    high.to_big_endian(&mut buf);
+    packed[..16].copy_from_slice(&buf[16..]);
+    low.to_big_endian(&mut buf);
+    packed[16..].copy_from_slice(&buf[16..]);
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_hash_zeroed() {
+        // Testing a user operation hash against the hash generated by the v0.7 EntryPoint
+        // contract's getUserOpHash() function with entrypoint address
+        // 0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc and chain ID 1337, for the PackedUserOperation
+        //
+        // PackedUserOperation = {
+        //     sender: '0x0000000000000000000000000000000000000000',
+        //     nonce: 0,
+        //     initCode: '0x',
+        //     callData: '0x',
+        //     accountGasLimits: bytes32(0),
+        //     preVerificationGas: 0,
+        //     gasFees: bytes32(0),
+        //     paymasterAndData: '0x',
+        //     signature: '0x',
+        //   }
+        //
+        // Hash: 0xdd4e6a940905af631df69197721a3ba90fd3b47c98cd5227029d1a7cbdc44f21
+        let operation = UserOperation::default();
+        let entry_point = "0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc"
+            .parse()
+            .unwrap();
+        let chain_id = 1337;
+        let hash = operation.op_hash(entry_point, chain_id);
+        assert_eq!(
+            hash,
+            "0xdd4e6a940905af631df69197721a3ba90fd3b47c98cd5227029d1a7cbdc44f21"
+                .parse()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        // Testing a user operation hash against the hash generated by the v0.7 EntryPoint
+        // contract's getUserOpHash() function with entrypoint address
+        // 0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc and chain ID 1337, for the PackedUserOperation
+        // assembled from:
+        //
+        // UserOperation = {
+        //     sender: '0x1306b01bc3e4ad202612d3843387e94737673f53',
+        //     nonce: 8942,
+        //     factory: '0x6942694269426942694269426942694269426942',
+        //     factoryData: '0xaabbccdd',
+        //     callData: '0x0000000000000000000000000000000000000000080085',
+        //     callGasLimit: 10000,
+        //     verificationGasLimit: 100000,
+        //     preVerificationGas: 100,
+        //     maxFeePerGas: 99999,
+        //     maxPriorityFeePerGas: 9999999,
+        //     paymaster: '0x0123456789abcdef0123456789abcdef01234567',
+        //     paymasterVerificationGasLimit: 30000,
+        //     paymasterPostOpGasLimit: 40000,
+        //     paymasterData: '0xdeadbeef',
+        //     signature:
+        //       '0xda0929f527cded8d0a1eaf2e8861d7f7e2d8160b7b13942f99dd367df4473a',
+        //   }
+        //
+        // Hash: 0x253858a00f78933083cf5feddb77c6b11b145dcc02e4ac66b79ba4683fd70294
+        let operation = UserOperation {
+            sender: "0x1306b01bc3e4ad202612d3843387e94737673f53"
+                .parse()
+                .unwrap(),
+            nonce: 8942.into(),
+            factory: Some(
+                "0x6942694269426942694269426942694269426942"
+                    .parse()
+                    .unwrap(),
+            ),
+            factory_data: Bytes::from_str("0xaabbccdd").unwrap(),
+            call_data: "0x0000000000000000000000000000000000000000080085"
+                .parse()
+                .unwrap(),
+            call_gas_limit: 10000.into(),
+            verification_gas_limit: 100000.into(),
+            pre_verification_gas: 100.into(),
+            max_fee_per_gas: 99999.into(),
+            max_priority_fee_per_gas: 9999999.into(),
+            paymaster: Some(
+                "0x0123456789abcdef0123456789abcdef01234567"
+                    .parse()
+                    .unwrap(),
+            ),
+            paymaster_verification_gas_limit: 30000.into(),
+            paymaster_post_op_gas_limit: 40000.into(),
+            paymaster_data: Bytes::from_str("0xdeadbeef").unwrap(),
+            signature: "0xda0929f527cded8d0a1eaf2e8861d7f7e2d8160b7b13942f99dd367df4473a"
+                .parse()
+                .unwrap(),
+        };
+        let entry_point = "0x66a15edcc3b50a663e72f1457ffd49b9ae284ddc"
+            .parse()
+            .unwrap();
+        let chain_id = 1337;
+        let hash = operation.op_hash(entry_point, chain_id);
+        assert_eq!(
+            hash,
+            "0x253858a00f78933083cf5feddb77c6b11b145dcc02e4ac66b79ba4683fd70294"
+                .parse()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_abi_encoded_size() {
+        let user_operation = UserOperation {
+            sender: "0x1306b01bc3e4ad202612d3843387e94737673f53"
+                .parse()
+                .unwrap(),
+            nonce: 8942.into(),
+            factory: Some(
+                "0x6942694269426942694269426942694269426942"
+                    .parse()
+                    .unwrap(),
+            ),
+            factory_data: Bytes::from_str("0xaabbccdd").unwrap(),
+            call_data: "0x0000000000000000000000000000000000000000080085"
+                .parse()
+                .unwrap(),
+            call_gas_limit: 10000.into(),
+            verification_gas_limit: 100000.into(),
+            pre_verification_gas: 100.into(),
+            max_fee_per_gas: 99999.into(),
+            max_priority_fee_per_gas: 9999999.into(),
+            paymaster: Some(
+                "0x0123456789abcdef0123456789abcdef01234567"
+                    .parse()
+                    .unwrap(),
+            ),
+            paymaster_verification_gas_limit: 30000.into(),
+            paymaster_post_op_gas_limit: 40000.into(),
+            paymaster_data: Bytes::from_str("0xdeadbeef").unwrap(),
+            signature: "0xda0929f527cded8d0a1eaf2e8861d7f7e2d8160b7b13942f99dd367df4473a"
+                .parse()
+                .unwrap(),
+        };
+
+        assert_eq!(user_operation.abi_encoded_size(), 576);
+    }
+}